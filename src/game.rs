@@ -18,18 +18,42 @@ impl Player {
     }
 }
 
+/// Protocol version this server understands. A `Connect` handshake carrying
+/// any other value is answered with `ServerResponse::Fail` and seats no
+/// player; other commands are not refused for lack of a successful `Connect`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Identifies one of the concurrent game rooms held by `Shared`.
+pub type RoomId = u32;
+
 #[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Connect { username: String },
+pub enum Command {
+    Connect {
+        username: String,
+        protocol_version: u32,
+    },
+    /// Open a fresh room and join it. The server replies with
+    /// `ServerResponse::RoomCreated` carrying the new `RoomId`.
+    CreateRoom,
+    /// Join an existing room by id. `Connect`/`Move`/`Reset` are accepted
+    /// only once the connection is in a room, through this or `CreateRoom`.
+    JoinRoom { room_id: RoomId },
     Move { move_id: usize, username: String },
     Reset,
+    /// Echoed back in reply to `ServerResponse::Ping` to prove the
+    /// connection is still alive.
+    Pong,
+}
+
+/// Deserialize a raw frame into a `Command`. Exposed so the connection
+/// loop can route lobby commands (`CreateRoom`/`JoinRoom`) to `Shared`
+/// before a room's `Game` ever sees them.
+pub fn decode(data: &[u8]) -> bincode::Result<Command> {
+    bincode::deserialize(data)
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ServerResponse {
-    Ok {
-        player_ip: IpAddr,
-    },
     Fail {
         message: String,
         player_ip: IpAddr,
@@ -40,13 +64,39 @@ pub enum ServerResponse {
         winner: Option<String>,
     },
     Reset,
+    /// Full snapshot of the game in progress, sent in reply to a successful
+    /// `Connect` handshake so a joining or reconnecting client can render the
+    /// board immediately instead of waiting for the next move.
+    State {
+        field: Vec<usize>,
+        active_player_color: Option<usize>,
+        players: Vec<String>,
+        winner: Option<String>,
+    },
+    /// Reply to `Command::CreateRoom` with the id of the newly opened room.
+    RoomCreated { room_id: RoomId },
+    /// Reply to a successful `Command::JoinRoom`.
+    Joined { room_id: RoomId },
+    /// Broadcast to the rest of a room when one of its players disconnects.
+    PlayerLeft { username: String },
+    /// Liveness probe sent periodically by the connection loop; a client
+    /// in good standing answers with `Command::Pong`.
+    Ping,
 }
 
+/// Width of a board row. `Game::field` is chunked into rows of this size so
+/// that win checks never scan across a row boundary.
+const BOARD_WIDTH: usize = 15;
+
+/// The four directions a line of stones can run in: horizontal, vertical,
+/// and the two diagonals. Each is a `(row_step, col_step)` pair.
+const WIN_DIRECTIONS: [(isize, isize); 4] = [(0, 1), (1, 0), (1, 1), (1, -1)];
+
 pub struct Game {
     pub players: Vec<Player>,
     pub active_player: Option<usize>,
     pub winner: Option<String>,
-    pub field: [usize; 255],
+    pub field: [usize; 225],
 }
 
 impl Game {
@@ -55,85 +105,128 @@ impl Game {
             players: vec![],
             active_player: None,
             winner: None,
-            field: [0; 255],
+            field: [0; 225],
         }
     }
 
     fn reset(&mut self) {
         self.active_player = None;
         self.winner = None;
-        self.field = [0; 255];
+        self.field = [0; 225];
     }
 
-    fn winner_check(&mut self, player_id: usize) {
-        self.horizontal_check(player_id);
-        [14, 15, 16].map(|shift| self.shift_check(player_id, shift));
-    }
-
-    fn horizontal_check(&mut self, player_id: usize) {
+    /// Check whether the stone just placed at `move_id` completes a run of
+    /// 5+ of `player_id`'s color along any of the four `WIN_DIRECTIONS`,
+    /// setting `self.winner` if so. Only the placed stone's row/col is ever
+    /// walked (in both the `+` and `-` sense of each direction), so this is
+    /// O(1) per move rather than scanning the whole board.
+    fn winner_check(&mut self, player_id: usize, move_id: usize) {
         let player = &self.players[player_id];
+        let Some(color) = player.color else {
+            return;
+        };
 
-        let rows = self.field.chunks(15);
-        for row in rows {
-            let mut win_line = vec![];
-            let mut idx = 0;
-            while idx < row.len() {
-                let cell_color = row[idx];
-                if cell_color == player.color.unwrap() {
-                    win_line.push(idx);
-                } else {
-                    win_line = vec![];
-                }
-                if win_line.len() >= 5 {
-                    self.winner = Some(player.name.clone());
-                    return;
-                }
-                idx += 1;
-            }
+        let row = move_id / BOARD_WIDTH;
+        let col = move_id % BOARD_WIDTH;
+        let wins = WIN_DIRECTIONS.iter().any(|&(row_step, col_step)| {
+            let forward = self.run_length(row, col, row_step, col_step, color);
+            let backward = self.run_length(row, col, -row_step, -col_step, color);
+            // The placed stone itself is counted by both halves, so subtract
+            // one copy of it back out.
+            forward + backward - 1 >= 5
+        });
+        if wins {
+            self.winner = Some(player.name.clone());
         }
     }
 
-    fn shift_check(&mut self, player_id: usize, shift: usize) {
-        let player = &self.players[player_id];
-        let mut idx = 0;
-        let mut win_line = vec![];
-        let winner_color = player.color.unwrap();
-        while idx < self.field.len() {
-            if self.field[idx] != winner_color {
-                idx += 1;
-                win_line = vec![];
-                continue;
-            }
-            win_line.push(idx);
-            let mut i = idx;
-            while i + shift < self.field.len() && self.field[i + shift] == winner_color {
-                win_line.push(i);
-                if win_line.len() >= 5 {
-                    self.winner = Some(player.name.clone());
-                    return;
-                }
-                i += shift;
+    /// Count consecutive `color` cells starting at `(row, col)` and stepping
+    /// by `(row_step, col_step)`, stopping at the board edge.
+    fn run_length(
+        &self,
+        row: usize,
+        col: usize,
+        row_step: isize,
+        col_step: isize,
+        color: usize,
+    ) -> usize {
+        let rows = (self.field.len() / BOARD_WIDTH) as isize;
+        let mut count = 0;
+        let mut row = row as isize;
+        let mut col = col as isize;
+        while row >= 0 && row < rows && col >= 0 && (col as usize) < BOARD_WIDTH {
+            if self.field[row as usize * BOARD_WIDTH + col as usize] != color {
+                break;
             }
-            win_line = vec![];
-            idx += 1;
+            count += 1;
+            row += row_step;
+            col += col_step;
         }
+        count
     }
 
-    pub fn handle_action(&mut self, data: &[u8], player_ip: IpAddr) -> ServerResponse {
-        let data = bincode::deserialize::<Command>(data);
-        match data {
-            Ok(Command::Reset) => {
+    /// Remove a disconnected player identified by `player_ip` from this room.
+    /// If an opponent is left, they win by forfeit; otherwise the room is
+    /// reset for the next match. Returns `None` if `player_ip` wasn't seated.
+    pub fn handle_disconnect(&mut self, player_ip: IpAddr) -> Option<ServerResponse> {
+        let leaver_id = self.players.iter().position(|p| p.ip == player_ip)?;
+        let username = self.players.remove(leaver_id).name;
+
+        if self.players.is_empty() {
+            self.reset();
+            return Some(ServerResponse::Reset);
+        }
+
+        self.winner = Some(self.players[0].name.clone());
+        self.active_player = None;
+
+        Some(ServerResponse::PlayerLeft { username })
+    }
+
+    /// Apply an already-decoded `Command` to this room's game state.
+    /// `CreateRoom`/`JoinRoom`/`Pong` are handled by the connection loop
+    /// in `main` before a room is selected, so they never reach a `Game`;
+    /// they are matched here only so this stays exhaustive.
+    pub fn handle_action(&mut self, command: Command, player_ip: IpAddr) -> ServerResponse {
+        match command {
+            Command::Reset => {
                 self.reset();
                 ServerResponse::Reset
             }
 
-            Ok(Command::Connect { username }) => {
+            Command::CreateRoom | Command::JoinRoom { .. } | Command::Pong => {
+                ServerResponse::Fail {
+                    message: "command is handled by the connection layer, not the game".to_string(),
+                    player_ip,
+                }
+            }
+
+            Command::Connect {
+                username,
+                protocol_version,
+            } => {
+                if protocol_version != PROTOCOL_VERSION {
+                    return ServerResponse::Fail {
+                        message: format!(
+                            "unsupported protocol version {} (server expects {})",
+                            protocol_version, PROTOCOL_VERSION
+                        ),
+                        player_ip,
+                    };
+                }
+
                 let new_player = Player::new(username, player_ip);
                 self.players.push(new_player);
-                ServerResponse::Ok { player_ip }
+
+                ServerResponse::State {
+                    field: self.field.to_vec(),
+                    active_player_color: self.active_player.and_then(|id| self.players[id].color),
+                    players: self.players.iter().map(|p| p.name.clone()).collect(),
+                    winner: self.winner.clone(),
+                }
             }
 
-            Ok(Command::Move { move_id, username }) => {
+            Command::Move { move_id, username } => {
                 if self.players.len() < 2 {
                     return ServerResponse::Fail {
                         message: "Wait for a second player to connect".to_string(),
@@ -141,6 +234,13 @@ impl Game {
                     };
                 }
 
+                if move_id >= self.field.len() {
+                    return ServerResponse::Fail {
+                        message: format!("move {} is off the board", move_id),
+                        player_ip,
+                    };
+                }
+
                 let (player_id, second_player_id) = if self.players[0].name == username {
                     (0_usize, 1_usize)
                 } else {
@@ -171,7 +271,7 @@ impl Game {
                 }
                 let color = self.players[player_id].color.unwrap();
                 self.field[move_id] = color;
-                self.winner_check(player_id);
+                self.winner_check(player_id, move_id);
 
                 ServerResponse::Move {
                     move_id,
@@ -179,9 +279,156 @@ impl Game {
                     winner: self.winner.clone(),
                 }
             }
-            Err(e) => {
-                panic!("{}", e)
-            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn game_with_one_color_player() -> Game {
+        let mut game = Game::new();
+        game.players.push(Player::new(
+            "alice".to_string(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+        ));
+        game.players[0].color = Some(1);
+        game
+    }
+
+    #[test]
+    fn winner_check_detects_five_in_a_row() {
+        let mut game = game_with_one_color_player();
+        for col in 0..5 {
+            game.field[col] = 1;
+        }
+        // The stone at col 4 is the one "just placed".
+        game.winner_check(0, 4);
+        assert_eq!(game.winner.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn winner_check_does_not_wrap_across_a_row_boundary() {
+        let mut game = game_with_one_color_player();
+        // Last four cells of row 0 plus the first cell of row 1 are contiguous
+        // in the flat `field` array but must not count as a run of five.
+        for col in (BOARD_WIDTH - 4)..BOARD_WIDTH {
+            game.field[col] = 1;
+        }
+        game.field[BOARD_WIDTH] = 1;
+        // The stone at row 1, col 0 (flat index `BOARD_WIDTH`) is the one
+        // "just placed".
+        game.winner_check(0, BOARD_WIDTH);
+        assert_eq!(game.winner, None);
+    }
+
+    #[test]
+    fn winner_check_detects_a_diagonal_run() {
+        let mut game = game_with_one_color_player();
+        for step in 0..5 {
+            game.field[step * BOARD_WIDTH + step] = 1;
+        }
+        // The stone at (4, 4) is the one "just placed".
+        game.winner_check(0, 4 * BOARD_WIDTH + 4);
+        assert_eq!(game.winner.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn run_length_stops_at_the_board_edge() {
+        let mut game = game_with_one_color_player();
+        // Place a run that would overshoot the last row if bounds were wrong.
+        let rows = game.field.len() / BOARD_WIDTH;
+        for row in (rows - 3)..rows {
+            game.field[row * BOARD_WIDTH] = 1;
+        }
+        let run = game.run_length(rows - 3, 0, 1, 0, 1);
+        assert_eq!(run, 3);
+    }
+
+    #[test]
+    fn handle_action_fails_lobby_commands_routed_to_a_game() {
+        let mut game = Game::new();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        for command in [Command::CreateRoom, Command::JoinRoom { room_id: 0 }, Command::Pong] {
+            assert!(matches!(
+                game.handle_action(command, ip),
+                ServerResponse::Fail { .. }
+            ));
+        }
+    }
+
+    #[test]
+    fn handle_action_rejects_an_out_of_range_move() {
+        let mut game = Game::new();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        game.players.push(Player::new("alice".to_string(), ip));
+        game.players.push(Player::new("bob".to_string(), ip));
+
+        let response = game.handle_action(
+            Command::Move {
+                move_id: game.field.len(),
+                username: "alice".to_string(),
+            },
+            ip,
+        );
+
+        assert!(matches!(response, ServerResponse::Fail { .. }));
+        assert_eq!(game.field, [0; 225]);
+    }
+
+    #[test]
+    fn decode_rejects_a_truncated_frame() {
+        let full = bincode::serialize(&Command::Reset).unwrap();
+        assert!(decode(&full[..full.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn handle_disconnect_resets_when_the_last_player_leaves() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let mut game = Game::new();
+        game.players.push(Player::new("alice".to_string(), ip));
+        game.field[0] = 1;
+
+        let response = game.handle_disconnect(ip);
+
+        assert!(matches!(response, Some(ServerResponse::Reset)));
+        assert!(game.players.is_empty());
+        assert_eq!(game.field, [0; 225]);
+    }
+
+    #[test]
+    fn handle_disconnect_forfeits_to_the_remaining_opponent() {
+        let alice_ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let bob_ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        let mut game = Game::new();
+        game.players.push(Player::new("alice".to_string(), alice_ip));
+        game.players.push(Player::new("bob".to_string(), bob_ip));
+        game.active_player = Some(0);
+
+        let response = game.handle_disconnect(alice_ip);
+
+        match response {
+            Some(ServerResponse::PlayerLeft { username }) => assert_eq!(username, "alice"),
+            other => panic!("expected PlayerLeft, got {:?}", other),
+        }
+        assert_eq!(game.winner.as_deref(), Some("bob"));
+        assert_eq!(game.active_player, None);
+    }
+
+    #[test]
+    fn handle_disconnect_ignores_an_unseated_ip() {
+        let mut game = Game::new();
+        game.players.push(Player::new(
+            "alice".to_string(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+        ));
+
+        let response = game.handle_disconnect(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)));
+
+        assert!(response.is_none());
+        assert_eq!(game.players.len(), 1);
+    }
+}
@@ -1,61 +1,120 @@
-use core::panic;
-use std::collections::HashMap;
-use std::io::{self, stdin, Read, Write};
+use std::collections::{HashMap, HashSet};
+use std::io::stdin;
 use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use serde::{Deserialize, Serialize};
-
+use bytes::Bytes;
+use futures::SinkExt;
 use std::error::Error;
 use std::net::SocketAddr;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, Mutex};
+use tokio::time::{interval, MissedTickBehavior};
 use tokio_stream::StreamExt;
-use tokio_util::codec::{BytesCodec, Framed};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 mod game;
-use game::{Game, ServerResponse};
+use game::{Command, Game, RoomId, ServerResponse};
+
+type Tx = mpsc::UnboundedSender<Vec<u8>>;
+type Rx = mpsc::UnboundedReceiver<Vec<u8>>;
 
-type Tx = mpsc::UnboundedSender<String>;
-type Rx = mpsc::UnboundedReceiver<String>;
+/// How often the connection loop sends its peer a `ServerResponse::Ping`,
+/// whether or not the peer has been idle.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// A peer that hasn't sent anything (including a `Pong`) in this long is
+/// considered dead and evicted.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(45);
 
 struct Shared {
     peers: HashMap<SocketAddr, Tx>,
+    /// The live game for every open room.
+    rooms: HashMap<RoomId, Arc<Mutex<Game>>>,
+    /// Who is currently in each room, for scoping broadcasts.
+    room_peers: HashMap<RoomId, HashSet<SocketAddr>>,
+    /// Which room each connected peer has joined, if any.
+    peer_rooms: HashMap<SocketAddr, RoomId>,
+    next_room_id: RoomId,
 }
 impl Shared {
     /// Create a new, empty, instance of `Shared`.
     fn new() -> Self {
         Shared {
             peers: HashMap::new(),
+            rooms: HashMap::new(),
+            room_peers: HashMap::new(),
+            peer_rooms: HashMap::new(),
+            next_room_id: 0,
         }
     }
 
-    /// Send a `LineCodec` encoded message to every peer, except
-    /// for the sender.
-    async fn broadcast(&mut self, sender: SocketAddr, message: &str) {
-        for peer in self.peers.iter_mut() {
-            if *peer.0 != sender {
-                let _ = peer.1.send(message.into());
+    /// Open a fresh, empty room and return its id.
+    fn create_room(&mut self) -> RoomId {
+        let room_id = self.next_room_id;
+        self.next_room_id += 1;
+        self.rooms.insert(room_id, Arc::new(Mutex::new(Game::new())));
+        self.room_peers.insert(room_id, HashSet::new());
+        room_id
+    }
+
+    /// Move `addr` into `room_id`, leaving whichever room it was in before.
+    /// Returns `false` if the room doesn't exist.
+    fn join_room(&mut self, addr: SocketAddr, room_id: RoomId) -> bool {
+        if !self.rooms.contains_key(&room_id) {
+            return false;
+        }
+        if let Some(previous) = self.peer_rooms.insert(addr, room_id) {
+            if let Some(members) = self.room_peers.get_mut(&previous) {
+                members.remove(&addr);
+            }
+        }
+        self.room_peers.entry(room_id).or_default().insert(addr);
+        true
+    }
+
+    /// Serialize `response` and send it to a single peer's mailbox.
+    fn send_to(&mut self, addr: SocketAddr, response: &ServerResponse) {
+        if let Some(tx) = self.peers.get(&addr) {
+            let bytes = bincode::serialize(response).expect("ServerResponse is always encodable");
+            let _ = tx.send(bytes);
+        }
+    }
+
+    /// Serialize `response` and send it to every peer that has joined `room_id`.
+    async fn broadcast_room(&mut self, room_id: RoomId, response: &ServerResponse) {
+        let bytes = bincode::serialize(response).expect("ServerResponse is always encodable");
+        if let Some(members) = self.room_peers.get(&room_id) {
+            for addr in members {
+                if let Some(tx) = self.peers.get(addr) {
+                    let _ = tx.send(bytes.clone());
+                }
             }
         }
     }
 }
 
 struct Peer {
-    lines: Framed<TcpStream, BytesCodec>,
+    lines: Framed<TcpStream, LengthDelimitedCodec>,
     rx: Rx,
+    /// When this peer last sent us anything, for idle-timeout eviction.
+    last_seen: Instant,
 }
 
 impl Peer {
     async fn new(
         state: Arc<Mutex<Shared>>,
-        lines: Framed<TcpStream, BytesCodec>,
-    ) -> io::Result<Peer> {
+        lines: Framed<TcpStream, LengthDelimitedCodec>,
+    ) -> std::io::Result<Peer> {
         let addr = lines.get_ref().peer_addr()?;
         let (tx, rx) = mpsc::unbounded_channel();
         state.lock().await.peers.insert(addr, tx);
 
-        Ok(Peer { lines, rx })
+        Ok(Peer {
+            lines,
+            rx,
+            last_seen: Instant::now(),
+        })
     }
 }
 
@@ -79,15 +138,13 @@ async fn main() {
     let listener = TcpListener::bind(address).await.unwrap();
     println!("Server listening on ip:port = {}", address);
     let state = Arc::new(Mutex::new(Shared::new()));
-    let game = Arc::new(Mutex::new(Game::new()));
 
     loop {
         let (stream, addr) = listener.accept().await.unwrap();
         let state = Arc::clone(&state);
-        let game = Arc::clone(&game);
 
         tokio::spawn(async move {
-            if let Err(e) = process(state, stream, addr, game).await {
+            if let Err(e) = process(state, stream, addr).await {
                 tracing::info!("an error occurred; error = {:?}", e);
             }
         });
@@ -98,115 +155,168 @@ async fn process(
     state: Arc<Mutex<Shared>>,
     stream: TcpStream,
     addr: SocketAddr,
-    game: Arc<Mutex<Game>>,
 ) -> Result<(), Box<dyn Error>> {
     let player_ip = stream.peer_addr().unwrap().ip();
-    let mut lines = Framed::new(stream, BytesCodec::new());
+    // `LengthDelimitedCodec` length-prefixes every frame, so a `Command`/`ServerResponse`
+    // split across TCP reads is buffered until complete instead of being handed to
+    // `bincode` half-formed.
+    let lines = Framed::new(stream, LengthDelimitedCodec::new());
     let mut peer = Peer::new(state.clone(), lines).await?;
+    let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     loop {
         tokio::select! {
-            // A message was received from a peer. Send it to the current user.
-            // Some(msg) = peer.rx.recv() => {
-                // peer.lines.send(&msg).await?;
-            // }
+            // A response was routed to this peer's mailbox. Forward it over the wire.
+            Some(frame) = peer.rx.recv() => {
+                if let Err(e) = peer.lines.send(Bytes::from(frame)).await {
+                    tracing::error!("failed to write to {}: {:?}", addr, e);
+                    disconnect(&state, addr, player_ip).await;
+                    break;
+                }
+            }
+            _ = heartbeat.tick() => {
+                if peer.last_seen.elapsed() >= IDLE_TIMEOUT {
+                    tracing::info!("evicting idle peer {}", addr);
+                    // A dead peer is exactly when this write is most likely to fail;
+                    // don't let that skip the cleanup below.
+                    while let Ok(frame) = peer.rx.try_recv() {
+                        if let Err(e) = peer.lines.send(Bytes::from(frame)).await {
+                            tracing::error!("failed to flush to {} before eviction: {:?}", addr, e);
+                            break;
+                        }
+                    }
+                    disconnect(&state, addr, player_ip).await;
+                    break;
+                }
+                state.lock().await.send_to(addr, &ServerResponse::Ping);
+            }
             result = peer.lines.next() => match result {
                 Some(Ok(data)) => {
-                    // let mut state = state.lock().await;
-                    // let msg = format!("{}: {}", username, msg);
-                    // state.broadcast(addr, &msg).await;
-                    let response = game.lock().await.handle_action(&data, player_ip);
+                    peer.last_seen = Instant::now();
+                    let command = match game::decode(&data) {
+                        Ok(command) => command,
+                        Err(e) => {
+                            let response = ServerResponse::Fail {
+                                message: format!("malformed command: {}", e),
+                                player_ip,
+                            };
+                            state.lock().await.send_to(addr, &response);
+                            continue;
+                        }
+                    };
+
+                    match command {
+                        // Already recorded via `peer.last_seen` above; nothing else to do.
+                        Command::Pong => {}
+                        Command::CreateRoom => {
+                            let mut state = state.lock().await;
+                            let room_id = state.create_room();
+                            state.join_room(addr, room_id);
+                            state.send_to(addr, &ServerResponse::RoomCreated { room_id });
+                        }
+                        Command::JoinRoom { room_id } => {
+                            let mut state = state.lock().await;
+                            if state.join_room(addr, room_id) {
+                                state.send_to(addr, &ServerResponse::Joined { room_id });
+                            } else {
+                                state.send_to(
+                                    addr,
+                                    &ServerResponse::Fail {
+                                        message: format!("room {} does not exist", room_id),
+                                        player_ip,
+                                    },
+                                );
+                            }
+                        }
+                        game_command => {
+                            let room_id = state.lock().await.peer_rooms.get(&addr).copied();
+                            let Some(room_id) = room_id else {
+                                state.lock().await.send_to(
+                                    addr,
+                                    &ServerResponse::Fail {
+                                        message: "join or create a room before playing".to_string(),
+                                        player_ip,
+                                    },
+                                );
+                                continue;
+                            };
+                            let game = state.lock().await.rooms.get(&room_id).unwrap().clone();
+                            let response = game.lock().await.handle_action(game_command, player_ip);
+                            let mut state = state.lock().await;
+                            match response {
+                                ServerResponse::Move { .. }
+                                | ServerResponse::Reset
+                                | ServerResponse::PlayerLeft { .. } => {
+                                    state.broadcast_room(room_id, &response).await;
+                                }
+                                ServerResponse::Fail { .. }
+                                | ServerResponse::State { .. }
+                                | ServerResponse::RoomCreated { .. }
+                                | ServerResponse::Joined { .. } => {
+                                    state.send_to(addr, &response);
+                                }
+                                // `handle_action` never produces a `Ping`; it's only ever sent
+                                // by the connection loop's own heartbeat tick.
+                                ServerResponse::Ping => unreachable!("Game::handle_action never returns Ping"),
+                            }
+                        }
+                    }
                 }
                 // An error occurred.
                 Some(Err(e)) => {
                     tracing::error!(
                         "an error occurred while processing messages for {}; error = {:?}",
-                        "kek",
+                        addr,
                         e
                     );
                 }
                 // The stream has been exhausted.
-                None => break,
+                None => {
+                    // Flush any responses still queued for this peer (e.g. the
+                    // ack for its last move) before tearing the connection down.
+                    // The peer is already gone at this point, so a write failure
+                    // here must not skip the cleanup below.
+                    while let Ok(frame) = peer.rx.try_recv() {
+                        if let Err(e) = peer.lines.send(Bytes::from(frame)).await {
+                            tracing::error!("failed to flush to {} before disconnect: {:?}", addr, e);
+                            break;
+                        }
+                    }
+                    disconnect(&state, addr, player_ip).await;
+                    break;
+                }
             },
         }
     }
 
-    // If this section is reached it means that the client was disconnected!
-    // Let's let everyone still connected know about it.
-    //     {
-    //         let mut state = state.lock().await;
-    //         state.peers.remove(&addr);
-
-    //         let msg = format!("{} has left the chat", username);
-    //         tracing::info!("{}", msg);
-    //         state.broadcast(addr, &msg).await;
-    //     }
-
     Ok(())
 }
 
-// // async fn process(socket: OwnedReadHalf, game: Arc<RwLock<Game>>, tx: Sender<ServerResponse>) {
-// //     let mut data = [0; 64];
-// //     let player_ip = socket.peer_addr().unwrap().ip();
-// //     socket.readable().await;
-// //     match socket.try_read(&mut data) {
-// //         Ok(size) => {
-// //             if size == 0 {
-// //                 return;
-//             }
-
-//             let response = game.write().unwrap().handle_action(&data, player_ip);
-//             tracing::error!("{:?}", response);
-//             if let Err(e) = tx.send(response) {
-//                 tracing::error!("Sending message to a transmitter failed: {}", e)
-//             };
-//         }
-//         Err(e) => {
-//             println!("Data read error: {}", e);
-//         }
-//     }
-// }
-
-// tokio::spawn(async move {
-//     // loop {
-//         match server_rx.try_recv() {
-//             Ok(response) => match response {
-//                 ServerResponse::Move {
-//                     move_id,
-//                     color,
-//                     winner,
-//                 } => {
-//                     let resp = bincode::serialize(&ServerResponse::Move {
-//                         move_id,
-//                         color,
-//                         winner,
-//                     })
-//                         .unwrap();
-//                     for (client, mut socket) in clients {
-//                         tracing::error!("{:?}", resp);
-//                         socket.write_all(&resp);
-//                     }
-//                 }
-//                 ServerResponse::Reset => {
-//                     let resp = bincode::serialize(&ServerResponse::Reset).unwrap();
-//                     for (addr, mut socket) in clients {
-//                         tracing::error!("{:?}", resp);
-//                         socket.write_all(&resp);
-//                     }
-//                 }
-//                 ServerResponse::Ok { player_ip } => {
-//                     let resp = bincode::serialize(&ServerResponse::Ok { player_ip }).unwrap();
-//                     clients.get_mut(&player_ip).unwrap().write_all(&resp);
-//                 }
-//                 ServerResponse::Fail { message, player_ip } => {
-//                     let resp =
-//                         bincode::serialize(&ServerResponse::Fail { message, player_ip }).unwrap();
-//                     clients.get_mut(&player_ip).unwrap().write_all(&resp);
-//                 }
-//             },
-//             Err(e) => {
-//                 tracing::error!("Failed to receive a value from the rx: {}", e);
-//             }
-//         }
-//     // }
-// });
+/// Remove a disconnected peer from the lobby and, if it had a seat in a
+/// room's game, run forfeit/reset cleanup and notify whoever is left.
+async fn disconnect(state: &Arc<Mutex<Shared>>, addr: SocketAddr, player_ip: IpAddr) {
+    let (room_id, game) = {
+        let mut state = state.lock().await;
+        state.peers.remove(&addr);
+        let room_id = state.peer_rooms.remove(&addr);
+        if let Some(room_id) = room_id {
+            if let Some(members) = state.room_peers.get_mut(&room_id) {
+                members.remove(&addr);
+            }
+        }
+        let game = room_id.and_then(|id| state.rooms.get(&id).cloned());
+        (room_id, game)
+    };
+
+    let (Some(room_id), Some(game)) = (room_id, game) else {
+        return;
+    };
+
+    let mut game = game.lock().await;
+    let response = game.handle_disconnect(player_ip);
+    drop(game);
+    if let Some(response) = response {
+        state.lock().await.broadcast_room(room_id, &response).await;
+    }
+}